@@ -1,21 +1,45 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(lang_items)]
 #![feature(alloc_error_handler)]
 #![feature(panic_info_message)]
 
+// outside tests the ckb-std macros below bring `alloc` into scope
+#[cfg(test)]
+extern crate alloc;
+
+use alloc::ffi::CString;
+use alloc::string::ToString;
 use alloc::vec::Vec;
 use ckb_std::{
     ckb_constants::{CellField, Source, SysError},
-    default_alloc, entry, syscalls,
+    default_alloc, entry,
+    syscalls::{self, SpawnArgs},
 };
 use ckb_types::{packed::Script, prelude::*};
 
+#[cfg(not(test))]
 entry!(main);
+#[cfg(not(test))]
 default_alloc!();
 
 const BUF_LEN: usize = 1024;
 const UDT_LEN: usize = 16;
+// owner lock hash carried at the head of Script#args
+const HASH_LEN: usize = 32;
+// xUDT flags field following the owner lock hash, 4 bytes little-endian
+const FLAGS_LEN: usize = 4;
+// the flags field selects exactly one mode below; these are mutually
+// exclusive codes, not combinable bits, so an issuer cannot compose e.g.
+// "extension" and "supply cap" by OR-ing them together.
+const FLAG_NONE: u32 = 0;
+// flags value selecting the extension-script layout
+const FLAG_EXTENSION: u32 = 1;
+// flags value selecting the burn-receipt layout
+const FLAG_BURN_RECEIPT: u32 = 2;
+// flags value selecting the supply-cap layout; 3 is left unassigned so that
+// no OR of two modes is itself a known mode.
+const FLAG_SUPPLY_CAP: u32 = 4;
 
 // Error codes
 #[repr(i8)]
@@ -25,6 +49,10 @@ enum Error {
     LengthNotEnough,
     Encoding, // data encoding error
     Amount,   // amount error
+    Overflow, // amount accumulation overflowed u128
+    ExtensionFailed, // an xUDT extension script rejected the transaction
+    SupplyCap,       // owner-mode mint exceeded the published per-transaction supply cap
+    BurnReceipt,     // net burn lacks a matching burn-receipt output cell
 }
 
 impl From<SysError> for Error {
@@ -39,6 +67,15 @@ impl From<SysError> for Error {
     }
 }
 
+fn hash_field_matches(buf: &[u8], len: usize, args: &[u8]) -> Result<bool, Error> {
+    // a loaded lock/type hash field must be exactly 32 bytes; a short or long
+    // read is a malformed cell rather than a non-match.
+    if len != buf.len() {
+        return Err(Error::Encoding);
+    }
+    Ok(args == buf)
+}
+
 fn check_owner_mode(args: &[u8]) -> Result<bool, Error> {
     // With owner lock script extracted, we will look through each input in the
     // current transaction to see if any unlocked cell uses owner lock.
@@ -58,18 +95,34 @@ fn check_owner_mode(args: &[u8]) -> Result<bool, Error> {
             Err(err) => return Err(err.into()),
         };
 
-        // invalid length of loaded data
-        if len != buf.len() {
-            return Err(Error::Encoding);
+        // check input's lock hash against the script args
+        if hash_field_matches(&buf, len, args)? {
+            return Ok(true);
         }
 
-        if args[..] == buf[..] {
-            return Ok(true);
+        // owner mode may also be triggered by an input whose *type* hash
+        // matches the args, e.g. an administrator cell guarded by a type
+        // script. Cells without a type script report `ItemMissing`, which we
+        // simply skip.
+        match syscalls::load_cell_by_field(&mut buf, 0, i, Source::Input, CellField::TypeHash) {
+            Ok(len) => {
+                if hash_field_matches(&buf, len, args)? {
+                    return Ok(true);
+                }
+            }
+            Err(SysError::ItemMissing) => {}
+            Err(err) => return Err(err.into()),
         }
         i += 1;
     }
 }
 
+fn accumulate_amount(acc: u128, amount: u128) -> Result<u128, Error> {
+    // a wrapping sum would let an attacker craft outputs that total past 2^128
+    // so the folded value looks smaller than the inputs, inflating supply.
+    acc.checked_add(amount).ok_or(Error::Overflow)
+}
+
 fn collect_inputs_amount() -> Result<u128, Error> {
     // let's loop through all input cells containing current UDTs,
     // and gather the sum of all input tokens.
@@ -89,7 +142,7 @@ fn collect_inputs_amount() -> Result<u128, Error> {
         if len != UDT_LEN {
             return Err(Error::Encoding);
         }
-        inputs_amount += u128::from_le_bytes(buf);
+        inputs_amount = accumulate_amount(inputs_amount, u128::from_le_bytes(buf))?;
         i += 1;
     }
     Ok(inputs_amount)
@@ -114,12 +167,177 @@ fn collect_outputs_amount() -> Result<u128, Error> {
         if len != UDT_LEN {
             return Err(Error::Encoding);
         }
-        outputs_amount += u128::from_le_bytes(buf);
+        outputs_amount = accumulate_amount(outputs_amount, u128::from_le_bytes(buf))?;
         i += 1;
     }
     Ok(outputs_amount)
 }
 
+fn find_cell_dep_by_data_hash(hash: &[u8]) -> Result<usize, Error> {
+    // Extension scripts are shipped as the data of a cell-dep; locate the dep
+    // whose data hash matches the referenced code hash.
+    let mut i = 0;
+    let mut buf = [0u8; HASH_LEN];
+    loop {
+        let len = match syscalls::load_cell_by_field(
+            &mut buf,
+            0,
+            i,
+            Source::CellDep,
+            CellField::DataHash,
+        ) {
+            Ok(len) => len,
+            Err(SysError::IndexOutOfBound) => return Err(Error::ExtensionFailed),
+            Err(err) => return Err(err.into()),
+        };
+
+        if len != buf.len() {
+            return Err(Error::Encoding);
+        }
+
+        if buf[..] == hash[..] {
+            return Ok(i);
+        }
+        i += 1;
+    }
+}
+
+fn parse_extension_hashes(extension: &[u8]) -> Result<&[u8], Error> {
+    // extension layout: [count: 4 bytes LE][code_hash: 32 bytes] * count
+    if extension.len() < FLAGS_LEN {
+        return Err(Error::Encoding);
+    }
+    let count = u32::from_le_bytes(extension[..FLAGS_LEN].try_into().unwrap()) as usize;
+    let hashes = &extension[FLAGS_LEN..];
+    if hashes.len() != count * HASH_LEN {
+        return Err(Error::Encoding);
+    }
+    Ok(hashes)
+}
+
+fn invoke_extensions(
+    extension: &[u8],
+    inputs_amount: u128,
+    outputs_amount: u128,
+) -> Result<(), Error> {
+    let hashes = parse_extension_hashes(extension)?;
+
+    // forward the running amounts to each extension as argv
+    let argv = [
+        CString::new(inputs_amount.to_string()).map_err(|_| Error::Encoding)?,
+        CString::new(outputs_amount.to_string()).map_err(|_| Error::Encoding)?,
+    ];
+    let argv: Vec<&core::ffi::CStr> = argv.iter().map(|s| s.as_c_str()).collect();
+    let argv_ptr: Vec<*const i8> = argv.iter().map(|s| s.as_ptr() as *const i8).collect();
+
+    // unlike `exec`, `spawn` launches the extension as a child process and
+    // returns its pid, so we can `wait` on it and move on to the next
+    // extension in the vector instead of handing off the VM for good. Each
+    // extension must exit 0 to approve the transaction; the first non-zero
+    // exit rejects it.
+    for hash in hashes.chunks_exact(HASH_LEN) {
+        let index = find_cell_dep_by_data_hash(hash)?;
+        // no fds are inherited by the extension; the list is still
+        // zero-terminated per the syscall's contract.
+        let inherited_fds = [0u64; 1];
+        let mut process_id: u64 = 0;
+        let mut spgs = SpawnArgs {
+            argc: argv_ptr.len() as u64,
+            argv: argv_ptr.as_ptr(),
+            process_id: &mut process_id,
+            inherited_fds: inherited_fds.as_ptr(),
+        };
+        syscalls::spawn(index, Source::CellDep, 0, 0, &mut spgs)?;
+        let exit_code = syscalls::wait(process_id)?;
+        if exit_code != 0 {
+            return Err(Error::ExtensionFailed);
+        }
+    }
+    Ok(())
+}
+
+fn decode_burn_amount(data: &[u8]) -> Result<u128, Error> {
+    // receipt data: [burned_amount: 16 bytes LE][target_chain_address..]; a
+    // real receipt carries a non-empty address, so the data is longer than the
+    // amount and only the leading 16 bytes are the burned amount.
+    if data.len() < UDT_LEN {
+        return Err(Error::Encoding);
+    }
+    Ok(u128::from_le_bytes(data[..UDT_LEN].try_into().unwrap()))
+}
+
+fn verify_burn_receipt(recipient: &[u8], delta: u128) -> Result<(), Error> {
+    // A net burn must be accompanied by a receipt output cell guarded by the
+    // recipient type script, whose data leads with the burned amount so a
+    // bridge relayer can pick the event up trustlessly.
+    let mut i = 0;
+    let mut hash_buf = [0u8; HASH_LEN];
+    loop {
+        match syscalls::load_cell_by_field(&mut hash_buf, 0, i, Source::Output, CellField::TypeHash)
+        {
+            Ok(len) => {
+                if len == hash_buf.len() && hash_buf[..] == recipient[..] {
+                    // the receipt data is longer than the amount (it also holds
+                    // the target chain address), so load into a full buffer and
+                    // decode the leading amount from the bytes actually read.
+                    let mut data = [0u8; BUF_LEN];
+                    let len = syscalls::load_cell_data(&mut data, 0, i, Source::Output)?;
+                    // `len` is the cell's full data length, not the number of
+                    // bytes actually copied into `data` — a receipt longer
+                    // than BUF_LEN must not turn into an out-of-bounds slice.
+                    let len = len.min(data.len());
+                    if decode_burn_amount(&data[..len])? != delta {
+                        return Err(Error::BurnReceipt);
+                    }
+                    return Ok(());
+                }
+            }
+            Err(SysError::ItemMissing) => {}
+            Err(SysError::IndexOutOfBound) => return Err(Error::BurnReceipt),
+            Err(err) => return Err(err.into()),
+        }
+        i += 1;
+    }
+}
+
+fn supply_cap(args: &[u8]) -> Result<Option<u128>, Error> {
+    // Optional supply cap, carried in the same flags-tagged extension region as
+    // the other features so it cannot collide with them: the owner lock hash,
+    // then `flags == FLAG_SUPPLY_CAP`, then a 16-byte little-endian u128. A bare
+    // trailing u128 would overlap the xUDT flags field parsed in `check()`.
+    //
+    // This bounds `GroupOutput` amounts in a *single* owner-mode transaction,
+    // not cumulative issuance — the type script has no state across
+    // transactions to total mints against. An issuer can still mint up to
+    // `cap` tokens in each of arbitrarily many owner-mode transactions, so
+    // integrators must not read this as a global max-supply guarantee.
+    let rest = &args[HASH_LEN..];
+    if rest.len() < FLAGS_LEN {
+        return Ok(None);
+    }
+    let flags = u32::from_le_bytes(rest[..FLAGS_LEN].try_into().unwrap());
+    match flags {
+        FLAG_SUPPLY_CAP => {
+            // the issuer selected this mode, so a truncated payload is a
+            // malformed cap, not the absence of one — fail loudly rather than
+            // silently minting uncapped.
+            if rest.len() < FLAGS_LEN + UDT_LEN {
+                return Err(Error::Encoding);
+            }
+            Ok(Some(u128::from_le_bytes(
+                rest[FLAGS_LEN..FLAGS_LEN + UDT_LEN].try_into().unwrap(),
+            )))
+        }
+        // another mode was selected for the (normal-mode) conservation check;
+        // owner-mode minting simply isn't capped.
+        FLAG_NONE | FLAG_EXTENSION | FLAG_BURN_RECEIPT => Ok(None),
+        // anything else is neither a known mode nor the absence of one; an
+        // issuer who meant to combine flags would land here rather than
+        // silently getting no cap at all.
+        _ => Err(Error::Encoding),
+    }
+}
+
 fn check() -> Result<(), Error> {
     // load current script
     // check verification branch is owner mode or normal mode
@@ -132,8 +350,25 @@ fn check() -> Result<(), Error> {
     // unpack the Script#args field
     let args: Vec<u8> = script.args().unpack();
 
+    // the owner lock hash always occupies the first 32 bytes; anything beyond
+    // it is the optional xUDT extension region.
+    if args.len() < HASH_LEN {
+        return Err(Error::Encoding);
+    }
+    let owner_lock_hash = &args[..HASH_LEN];
+
     // return success if owner mode is true
-    if check_owner_mode(&args)? {
+    if check_owner_mode(owner_lock_hash)? {
+        // even the issuer is bound by an optional cap published in the args,
+        // so holders can verify it on-chain. NOTE: the type script only sees
+        // this transaction's `GroupOutput`, so the cap bounds the amount minted
+        // *per transaction*, not cumulative supply across transactions — an
+        // issuer can still mint up to `cap` in each of many owner-mode txs.
+        if let Some(cap) = supply_cap(&args)? {
+            if collect_outputs_amount()? > cap {
+                return Err(Error::SupplyCap);
+            }
+        }
         return Ok(());
     }
 
@@ -144,9 +379,41 @@ fn check() -> Result<(), Error> {
         return Err(Error::Amount);
     }
 
+    // parse the xUDT flags following the owner lock hash
+    let rest = &args[HASH_LEN..];
+    if rest.len() >= FLAGS_LEN {
+        let flags = u32::from_le_bytes(rest[..FLAGS_LEN].try_into().unwrap());
+        let extension = &rest[FLAGS_LEN..];
+        match flags {
+            FLAG_NONE => {}
+            FLAG_EXTENSION => {
+                // conservation holds; now run every attached extension script
+                invoke_extensions(extension, inputs_amount, outputs_amount)?;
+            }
+            FLAG_BURN_RECEIPT => {
+                // a net burn must leave an auditable receipt for bridge back-ends
+                if inputs_amount > outputs_amount {
+                    if extension.len() < HASH_LEN {
+                        return Err(Error::Encoding);
+                    }
+                    let delta = inputs_amount - outputs_amount;
+                    verify_burn_receipt(&extension[..HASH_LEN], delta)?;
+                }
+            }
+            // supply cap only constrains owner-mode minting; nothing to do
+            // for the conservation check on this (non-owner-mode) path.
+            FLAG_SUPPLY_CAP => {}
+            // neither a known mode nor the absence of one — e.g. an issuer
+            // who tried to OR two modes together — so reject it outright
+            // instead of silently running no feature at all.
+            _ => return Err(Error::Encoding),
+        }
+    }
+
     Ok(())
 }
 
+#[cfg(not(test))]
 #[no_mangle]
 fn main() -> i8 {
     match check() {
@@ -154,3 +421,129 @@ fn main() -> i8 {
         Err(err) => err as i8,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    #[test]
+    fn accumulate_amount_sums_in_range() {
+        assert_eq!(accumulate_amount(10, 5).unwrap(), 15);
+        assert_eq!(accumulate_amount(0, u128::MAX).unwrap(), u128::MAX);
+    }
+
+    #[test]
+    fn accumulate_amount_rejects_overflow() {
+        // the running sum wrapping past u128::MAX must be rejected, not wrapped
+        assert!(matches!(
+            accumulate_amount(u128::MAX, 1),
+            Err(Error::Overflow)
+        ));
+        assert!(matches!(
+            accumulate_amount(u128::MAX - 3, 10),
+            Err(Error::Overflow)
+        ));
+    }
+
+    #[test]
+    fn parse_extension_hashes_accepts_single() {
+        let mut ext = std::vec::Vec::new();
+        ext.extend_from_slice(&1u32.to_le_bytes());
+        ext.extend_from_slice(&[7u8; HASH_LEN]);
+        let hashes = parse_extension_hashes(&ext).unwrap();
+        assert_eq!(hashes, &[7u8; HASH_LEN][..]);
+    }
+
+    #[test]
+    fn parse_extension_hashes_rejects_truncated_and_mismatched() {
+        // count claims two hashes but only one is present
+        let mut ext = std::vec::Vec::new();
+        ext.extend_from_slice(&2u32.to_le_bytes());
+        ext.extend_from_slice(&[7u8; HASH_LEN]);
+        assert!(matches!(parse_extension_hashes(&ext), Err(Error::Encoding)));
+        // header shorter than the flags field
+        assert!(matches!(parse_extension_hashes(&[0u8; 2]), Err(Error::Encoding)));
+    }
+
+    #[test]
+    fn owner_mode_matches_either_hash_field() {
+        // the same comparison backs both the lock-hash and type-hash passes, so
+        // a match on either field triggers owner mode.
+        let args = [9u8; HASH_LEN];
+        let hit = [9u8; HASH_LEN];
+        let miss = [1u8; HASH_LEN];
+        assert!(hash_field_matches(&hit, HASH_LEN, &args).unwrap());
+        assert!(!hash_field_matches(&miss, HASH_LEN, &args).unwrap());
+    }
+
+    #[test]
+    fn owner_mode_rejects_wrong_length_field() {
+        let args = [9u8; HASH_LEN];
+        let buf = [9u8; HASH_LEN];
+        assert!(matches!(
+            hash_field_matches(&buf, HASH_LEN - 1, &args),
+            Err(Error::Encoding)
+        ));
+    }
+
+    fn args_with_flag(flag: u32, tail: &[u8]) -> std::vec::Vec<u8> {
+        let mut args = std::vec::Vec::new();
+        args.extend_from_slice(&[0u8; HASH_LEN]);
+        args.extend_from_slice(&flag.to_le_bytes());
+        args.extend_from_slice(tail);
+        args
+    }
+
+    #[test]
+    fn supply_cap_parses_only_under_its_flag() {
+        let cap = 1_000u128;
+        let args = args_with_flag(FLAG_SUPPLY_CAP, &cap.to_le_bytes());
+        assert_eq!(supply_cap(&args).unwrap(), Some(cap));
+    }
+
+    #[test]
+    fn supply_cap_does_not_collide_with_other_flags() {
+        // a bare owner hash, or one tagged with another feature's flag, must not
+        // be read as a cap, so the cap can never hijack the flags region.
+        assert_eq!(supply_cap(&[0u8; HASH_LEN]).unwrap(), None);
+        let ext = args_with_flag(FLAG_EXTENSION, &[0u8; UDT_LEN]);
+        assert_eq!(supply_cap(&ext).unwrap(), None);
+        let burn = args_with_flag(FLAG_BURN_RECEIPT, &[0u8; HASH_LEN]);
+        assert_eq!(supply_cap(&burn).unwrap(), None);
+    }
+
+    #[test]
+    fn supply_cap_rejects_truncated_payload_under_its_flag() {
+        // the issuer selected the cap mode, so a short payload is malformed,
+        // not "no cap" — minting must not fall through to uncapped.
+        let args = args_with_flag(FLAG_SUPPLY_CAP, &[0u8; UDT_LEN - 1]);
+        assert!(matches!(supply_cap(&args), Err(Error::Encoding)));
+    }
+
+    #[test]
+    fn supply_cap_rejects_unrecognized_flags() {
+        // an issuer OR-ing two modes together (e.g. EXTENSION | SUPPLY_CAP)
+        // must not silently end up with no cap at all.
+        let args = args_with_flag(FLAG_EXTENSION | FLAG_SUPPLY_CAP, &[0u8; UDT_LEN]);
+        assert!(matches!(supply_cap(&args), Err(Error::Encoding)));
+    }
+
+    #[test]
+    fn decode_burn_amount_reads_leading_amount_past_address() {
+        // a real receipt has a non-empty address trailing the amount
+        let mut data = std::vec::Vec::new();
+        data.extend_from_slice(&42u128.to_le_bytes());
+        data.extend_from_slice(b"ckt1q...target-address");
+        assert_eq!(decode_burn_amount(&data).unwrap(), 42);
+    }
+
+    #[test]
+    fn decode_burn_amount_rejects_short_data() {
+        assert!(matches!(
+            decode_burn_amount(&[0u8; UDT_LEN - 1]),
+            Err(Error::Encoding)
+        ));
+    }
+}